@@ -1,12 +1,19 @@
 use std::io::Write;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
 use std::{fmt::Display, io::Read};
 
 use serde::{Deserialize, Serialize};
+use smol::lock::Mutex;
 use thiserror::Error;
 
 use encoding::all::ISO_8859_1;
 use encoding::{DecoderTrap, Encoding};
 
+mod local;
+
+pub use local::{LocalDevice, ProtocolVersion};
+
 struct TuyaBizType;
 
 impl Serialize for TuyaBizType {
@@ -64,6 +71,24 @@ impl Credentials {
     }
 }
 
+struct GrantTypeRefreshToken;
+
+impl Serialize for GrantTypeRefreshToken {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        "refresh_token".serialize(serializer)
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RefreshRequest {
+    grant_type: GrantTypeRefreshToken,
+    refresh_token: String,
+}
+
 #[derive(Debug)]
 enum TuyaEndpoint {
     Login,
@@ -84,7 +109,7 @@ fn endpoint_uri(endpoint: TuyaEndpoint) -> String {
 }
 
 pub struct TuyaApi {
-    tokens: TuyaApiTokens,
+    session: Mutex<Session>,
 }
 
 #[derive(Debug, Error)]
@@ -97,6 +122,14 @@ pub enum Error {
     Deserializing(#[source] serde_json::Error),
     #[error("API error: {0}")]
     Api(String),
+    #[error("local device I/O failed: {0}")]
+    Io(#[source] std::io::Error),
+    #[error("local device protocol error: {0}")]
+    Local(String),
+    #[error("access token expired and no refresh token is available; re-login is required")]
+    TokenExpired,
+    #[error("invalid color: {0}")]
+    InvalidColor(String),
 }
 
 impl From<surf::Error> for Error {
@@ -146,23 +179,92 @@ struct LightResponse {
     online: bool,
     state: String,
     color_temp: i32,
+    #[serde(default)]
+    color: Option<ColorResponse>,
 }
 
 #[derive(Deserialize, Debug)]
-#[serde(tag = "dev_type")]
-enum ScanDevice {
-    #[serde(rename = "light")]
-    Light {
-        data: LightResponse,
-        name: String,
-        id: String,
-    },
-    Unknown,
+struct ColorResponse {
+    hue: u16,
+    saturation: u16,
+    brightness: u16,
+}
+
+impl LightResponse {
+    fn into_state(self) -> Result<LightState, Error> {
+        let brightness = self
+            .brightness
+            .parse::<f64>()
+            .map_err(|e| Error::Api(format!("invalid brightness `{}`: {}", self.brightness, e)))?;
+        Ok(LightState {
+            on: matches!(self.state.as_str(), "true" | "1" | "on"),
+            online: self.online,
+            brightness: ((brightness / 100.) * 255.) as u8,
+            color_mode: ColorMode::from_wire(&self.color_mode),
+            color_temp: 2700 + (((self.color_temp - 1000).max(0) as f64 / 9000.) * 3800.) as u32,
+            color: self.color.map(|color| HsbColor {
+                hue: color.hue,
+                saturation: color.saturation as f64 / 1000.,
+                brightness: color.brightness,
+            }),
+        })
+    }
+}
+
+/// The device's reported color mode, mirroring Tuya's `colour`/`white`/scene
+/// values. `color` on [`LightState`] is only meaningful when this is
+/// [`ColorMode::Colour`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    White,
+    Colour,
+    Scene,
+    Music,
+    Other,
+}
+
+impl ColorMode {
+    fn from_wire(value: &str) -> Self {
+        match value {
+            "white" => ColorMode::White,
+            "colour" => ColorMode::Colour,
+            "scene" => ColorMode::Scene,
+            "music" => ColorMode::Music,
+            _ => ColorMode::Other,
+        }
+    }
+}
+
+/// A device's state as reported by [`TuyaApi::query`], with Tuya's wire
+/// ranges converted back to the units the rest of this crate's API uses.
+#[derive(Debug, Clone)]
+pub struct LightState {
+    pub on: bool,
+    pub online: bool,
+    pub brightness: u8,
+    pub color_mode: ColorMode,
+    pub color_temp: u32,
+    pub color: Option<HsbColor>,
+}
+
+/// Raw shape of one entry in the discovery payload, before we sort it into a
+/// [`Device`] variant by `dev_type`. Unknown `dev_type`s are kept (as
+/// [`Device::Other`]) rather than discarded, since sockets/switches/etc. all
+/// share this same envelope.
+#[derive(Deserialize, Debug)]
+struct RawScanDevice {
+    dev_type: String,
+    name: String,
+    id: String,
+    ip: Option<String>,
+    local_key: Option<String>,
+    #[serde(rename = "ver")]
+    protocol_version: Option<ProtocolVersion>,
 }
 
 #[derive(Deserialize, Debug)]
 struct ScanDevices {
-    devices: Vec<ScanDevice>,
+    devices: Vec<RawScanDevice>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -170,33 +272,188 @@ struct ScanResponse {
     payload: ScanDevices,
 }
 
+#[derive(Deserialize, Debug)]
+struct QueryResponse {
+    payload: LightResponse,
+}
+
+/// Fields shared by every kind of Tuya device this crate can control.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Light {
-    pub name: String,
+pub struct DeviceInfo {
+    name: String,
     device_id: DeviceId,
+    local_key: Option<String>,
+    ip: Option<IpAddr>,
+    protocol_version: Option<ProtocolVersion>,
 }
 
-impl Light {
+impl DeviceInfo {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn id(&self) -> &str {
         &self.device_id.0
     }
+
+    /// Build a handle for controlling this device directly over the LAN,
+    /// bypassing the cloud. No connection is made until a command is sent.
+    ///
+    /// Requires that `ip` and `local_key` were populated by [`TuyaApi::scan`];
+    /// returns `Error::Local` if either is missing (e.g. the device was
+    /// loaded from a cache that predates local-control support).
+    pub fn local(&self) -> Result<LocalDevice, Error> {
+        let ip = self
+            .ip
+            .ok_or_else(|| Error::Local(format!("{} has no known LAN address", self.name)))?;
+        let local_key = self
+            .local_key
+            .as_deref()
+            .ok_or_else(|| Error::Local(format!("{} has no known local key", self.name)))?;
+        LocalDevice::new(
+            ip,
+            self.device_id.0.clone(),
+            local_key,
+            self.protocol_version.unwrap_or_default(),
+        )
+    }
+}
+
+/// Any device that can be switched on or off, regardless of what else it can
+/// do. Implemented by every [`Device`] variant so [`TuyaApi::set_state`]
+/// doesn't need to know which kind of device it's talking to.
+pub trait Switchable {
+    fn id(&self) -> &str;
 }
 
-pub struct AccessToken(String);
+macro_rules! device_kind {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Serialize, Deserialize, Debug, Clone)]
+        pub struct $name {
+            #[serde(flatten)]
+            info: DeviceInfo,
+        }
 
-impl AccessToken {
-    pub fn write_to<T: Write>(&self, mut writer: T) -> Result<(), std::io::Error> {
-        writer.write_all(self.0.as_bytes())
+        impl $name {
+            pub fn name(&self) -> &str {
+                self.info.name()
+            }
+
+            pub fn id(&self) -> &str {
+                self.info.id()
+            }
+
+            pub fn local(&self) -> Result<LocalDevice, Error> {
+                self.info.local()
+            }
+        }
+
+        impl Switchable for $name {
+            fn id(&self) -> &str {
+                self.info.id()
+            }
+        }
+    };
+}
+
+device_kind!(
+    /// A dimmable, color-capable light.
+    Light
+);
+device_kind!(
+    /// A switchable device with no brightness or color control, e.g. a wall
+    /// switch.
+    Switch
+);
+device_kind!(
+    /// A switchable power outlet.
+    Socket
+);
+
+/// A device discovered on the account, sorted by its reported `dev_type`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Device {
+    Light(Light),
+    Switch(Switch),
+    Socket(Socket),
+    /// Hardware with a `dev_type` this crate doesn't specifically model yet.
+    /// Kept around (rather than dropped) so it's still enumerable and
+    /// switchable.
+    Other { dev_type: String, info: DeviceInfo },
+}
+
+impl Device {
+    pub fn name(&self) -> &str {
+        match self {
+            Device::Light(device) => device.name(),
+            Device::Switch(device) => device.name(),
+            Device::Socket(device) => device.name(),
+            Device::Other { info, .. } => info.name(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            Device::Light(device) => device.id(),
+            Device::Switch(device) => device.id(),
+            Device::Socket(device) => device.id(),
+            Device::Other { info, .. } => info.id(),
+        }
     }
-    pub fn read_from<T: Read>(mut reader: T) -> Result<Self, std::io::Error> {
-        let mut buf = String::new();
-        reader.read_to_string(&mut buf)?;
-        Ok(AccessToken(buf))
+}
+
+impl Switchable for Device {
+    fn id(&self) -> &str {
+        Device::id(self)
     }
 }
 
-#[derive(Serialize, Deserialize)]
-struct RefreshToken(String);
+struct AccessToken(String);
+
+/// A persistable OAuth session: the access token used to authenticate
+/// requests, the refresh token used to renew it, and the absolute time the
+/// access token stops being valid.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Session {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_at: SystemTime,
+}
+
+impl Session {
+    fn from_tokens(tokens: TuyaApiTokens) -> Self {
+        let expires_at = tokens
+            .expires_in
+            .and_then(|secs| u64::try_from(secs).ok())
+            .map(|secs| SystemTime::now() + Duration::from_secs(secs))
+            .unwrap_or_else(|| SystemTime::now() + Duration::from_secs(365 * 24 * 60 * 60));
+        Session {
+            access_token: tokens.access_token,
+            refresh_token: tokens.refresh_token,
+            expires_at,
+        }
+    }
+
+    fn access_token(&self) -> AccessToken {
+        AccessToken(self.access_token.clone())
+    }
+
+    fn needs_refresh(&self) -> bool {
+        self.expires_at
+            .duration_since(SystemTime::now())
+            .map(|remaining| remaining < Duration::from_secs(60))
+            .unwrap_or(true)
+    }
+
+    pub fn write_to<T: Write>(&self, writer: T) -> Result<(), std::io::Error> {
+        serde_json::to_writer(writer, self).map_err(std::io::Error::other)
+    }
+
+    pub fn read_from<T: Read>(reader: T) -> Result<Self, std::io::Error> {
+        serde_json::from_reader(reader).map_err(std::io::Error::other)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(transparent)]
@@ -322,12 +579,6 @@ impl Serialize for TuyaRequest {
     }
 }
 
-impl TuyaApiTokens {
-    fn access_token(&self) -> AccessToken {
-        AccessToken(self.access_token.clone())
-    }
-}
-
 #[derive(Deserialize, Debug)]
 #[serde(tag = "code")]
 enum ResponseType {
@@ -343,37 +594,78 @@ struct SetStateResponse {
 
 impl TuyaApi {
     pub async fn new<T: AsRef<str>, U: AsRef<str>>(user: T, pass: U) -> Result<Self, Error> {
+        let tokens = Self::post_login(&Credentials::new(user.as_ref().into(), pass.as_ref().into()))
+            .await?;
+        Ok(TuyaApi {
+            session: Mutex::new(Session::from_tokens(tokens)),
+        })
+    }
+
+    /// The current session, including any refresh that happened during a
+    /// prior call. Callers should re-persist this after every API call that
+    /// might have triggered a refresh (see [`Session::write_to`]).
+    pub async fn session(&self) -> Session {
+        self.session.lock().await.clone()
+    }
+
+    pub fn from_session(session: Session) -> Self {
+        TuyaApi {
+            session: Mutex::new(session),
+        }
+    }
+
+    async fn post_login<T: Serialize>(body: &T) -> Result<TuyaApiTokens, Error> {
         let mut data = surf::post(endpoint_uri(TuyaEndpoint::Login))
-            .body(surf::Body::from_form(&Credentials::new(
-                user.as_ref().into(),
-                pass.as_ref().into(),
-            ))?)
+            .body(surf::Body::from_form(body)?)
             .content_type("application/x-www-form-urlencoded")
             .send()
             .await?;
         let data = ISO_8859_1
             .decode(&data.body_bytes().await?, DecoderTrap::Strict)
             .map_err(|e| Error::Encoding(e.into_owned()))?;
-        let tokens = serde_json::from_str::<LoginResponse>(&data)?.to_result()?;
-        Ok(TuyaApi { tokens })
-    }
-    pub fn dump_token(&self) -> AccessToken {
-        self.tokens.access_token()
+        serde_json::from_str::<LoginResponse>(&data)?.to_result()
     }
-    pub fn from_token(token: AccessToken) -> Self {
-        TuyaApi {
-            tokens: TuyaApiTokens {
-                access_token: token.0,
-                refresh_token: None,
-                token_type: None,
-                expires_in: None,
-            },
+
+    /// Refreshes the access token in place if it's within 60 seconds of
+    /// expiring, so every outgoing request can assume a live token.
+    ///
+    /// Holds the session lock for the whole check-refresh-write sequence
+    /// (including the network round-trip) so that concurrent callers, e.g.
+    /// [`TuyaApi::apply_scene`]'s concurrent dispatch, serialize on it
+    /// instead of each independently observing a stale token and firing
+    /// redundant refreshes. A waiting caller re-checks `needs_refresh()`
+    /// once it acquires the lock, so only the first one actually refreshes.
+    async fn ensure_fresh(&self) -> Result<(), Error> {
+        let mut session = self.session.lock().await;
+        if !session.needs_refresh() {
+            return Ok(());
+        }
+        let refresh_token = session.refresh_token.clone().ok_or(Error::TokenExpired)?;
+        let tokens = Self::post_login(&RefreshRequest {
+            grant_type: GrantTypeRefreshToken,
+            refresh_token: refresh_token.clone(),
+        })
+        .await?;
+        let mut new_session = Session::from_tokens(tokens);
+        // Some refresh responses omit `refresh_token`, meaning the old one
+        // is still valid and should keep being reused; don't let a missing
+        // field here permanently strand the session without one.
+        if new_session.refresh_token.is_none() {
+            new_session.refresh_token = Some(refresh_token);
         }
+        *session = new_session;
+        Ok(())
+    }
+
+    async fn access_token(&self) -> AccessToken {
+        self.session.lock().await.access_token()
     }
-    pub async fn scan(&self) -> Result<Vec<Light>, Error> {
+
+    pub async fn scan(&self) -> Result<Vec<Device>, Error> {
+        self.ensure_fresh().await?;
         let request = TuyaRequest {
             command: TuyaCommand::Discovery,
-            access_token: self.tokens.access_token(),
+            access_token: self.access_token().await,
         };
         let data: ScanResponse = surf::post(endpoint_uri(TuyaEndpoint::Skill))
             .content_type("application/json")
@@ -384,23 +676,31 @@ impl TuyaApi {
             .payload
             .devices
             .into_iter()
-            .filter_map(|item| match item {
-                ScanDevice::Light {
-                    name,
-                    id: device_id,
-                    ..
-                } => Some(Light {
-                    name,
-                    device_id: DeviceId(device_id),
-                }),
-                ScanDevice::Unknown => None,
+            .map(|raw| {
+                let info = DeviceInfo {
+                    name: raw.name,
+                    device_id: DeviceId(raw.id),
+                    local_key: raw.local_key,
+                    ip: raw.ip.and_then(|ip| ip.parse().ok()),
+                    protocol_version: raw.protocol_version,
+                };
+                match raw.dev_type.as_str() {
+                    "light" => Device::Light(Light { info }),
+                    "switch" => Device::Switch(Switch { info }),
+                    "socket" => Device::Socket(Socket { info }),
+                    dev_type => Device::Other {
+                        dev_type: dev_type.to_owned(),
+                        info,
+                    },
+                }
             })
             .collect())
     }
     async fn send_state_command(&self, command: TuyaCommand) -> Result<(), Error> {
+        self.ensure_fresh().await?;
         let request = TuyaRequest {
             command,
-            access_token: self.tokens.access_token(),
+            access_token: self.access_token().await,
         };
         let data: SetStateResponse = surf::post(endpoint_uri(TuyaEndpoint::Skill))
             .content_type("application/json")
@@ -413,23 +713,24 @@ impl TuyaApi {
             Err(Error::Api(format!("{:?}", data.header)))
         }
     }
-    pub async fn set_state(&self, light: &Light, state: State) -> Result<(), Error> {
+    /// Turn any switchable device (light, switch, or socket) on or off.
+    pub async fn set_state<D: Switchable>(&self, device: &D, state: State) -> Result<(), Error> {
         self.send_state_command(TuyaCommand::TurnOnOff {
-            device_id: light.device_id.clone(),
+            device_id: DeviceId(device.id().to_owned()),
             state,
         })
         .await
     }
     pub async fn set_brightness(&self, light: &Light, brightness: u8) -> Result<(), Error> {
         self.send_state_command(TuyaCommand::SetBrightness {
-            device_id: light.device_id.clone(),
-            brightness: ((brightness as f64 / 255.) * 100.) as u8,
+            device_id: light.info.device_id.clone(),
+            brightness: brightness_to_wire(brightness),
         })
         .await
     }
     pub async fn set_color(&self, light: &Light, color: HsbColor) -> Result<(), Error> {
         self.send_state_command(TuyaCommand::SetColor {
-            device_id: light.device_id.clone(),
+            device_id: light.info.device_id.clone(),
             color,
         })
         .await
@@ -440,35 +741,270 @@ impl TuyaApi {
         temperature: u32,
     ) -> Result<(), Error> {
         self.send_state_command(TuyaCommand::SetColorTemperature {
-            device_id: light.device_id.clone(),
-            temperature: (1000. + (((temperature.min(6500) - 2700) as f64) / 3800.) * 9000.) as u32,
+            device_id: light.info.device_id.clone(),
+            temperature: temperature_to_wire(temperature),
         })
         .await
     }
-    async fn query(&self, light: &Light) -> Result<(), Error> {
+    /// Fetch a device's current state: on/off, brightness, color mode,
+    /// color temperature, and (when in colour mode) its color.
+    pub async fn query(&self, light: &Light) -> Result<LightState, Error> {
+        self.ensure_fresh().await?;
         let request = TuyaRequest {
             command: TuyaCommand::QueryDevice {
-                device_id: light.device_id.clone(),
+                device_id: light.info.device_id.clone(),
             },
-            access_token: self.tokens.access_token(),
+            access_token: self.access_token().await,
         };
-        let data = surf::post(endpoint_uri(TuyaEndpoint::Skill))
+        let data: QueryResponse = surf::post(endpoint_uri(TuyaEndpoint::Skill))
             .content_type("application/json")
             .body(surf::Body::from_json(&request)?)
-            .recv_string()
+            .recv_json()
             .await?;
-        println!("{}", data);
-        Ok(())
+        data.payload.into_state()
     }
+
+    /// Apply every change queued on `scene` concurrently, rather than
+    /// sending them one at a time. One unreachable device doesn't stop the
+    /// others; check the returned report for per-device outcomes.
+    pub async fn apply_scene(&self, scene: &Scene) -> SceneReport {
+        let results = futures::future::join_all(scene.changes.iter().map(|(device_id, change)| {
+            let command = match change {
+                SceneChange::State(state) => TuyaCommand::TurnOnOff {
+                    device_id: DeviceId(device_id.clone()),
+                    state: *state,
+                },
+                SceneChange::Brightness(brightness) => TuyaCommand::SetBrightness {
+                    device_id: DeviceId(device_id.clone()),
+                    brightness: brightness_to_wire(*brightness),
+                },
+                SceneChange::Color(color) => TuyaCommand::SetColor {
+                    device_id: DeviceId(device_id.clone()),
+                    color: *color,
+                },
+                SceneChange::ColorTemperature(temperature) => TuyaCommand::SetColorTemperature {
+                    device_id: DeviceId(device_id.clone()),
+                    temperature: temperature_to_wire(*temperature),
+                },
+            };
+            self.send_state_command(command)
+        }))
+        .await;
+        SceneReport {
+            results: scene
+                .changes
+                .iter()
+                .map(|(device_id, _)| device_id.clone())
+                .zip(results)
+                .collect(),
+        }
+    }
+}
+
+fn brightness_to_wire(brightness: u8) -> u8 {
+    ((brightness as f64 / 255.) * 100.) as u8
+}
+
+fn temperature_to_wire(temperature: u32) -> u32 {
+    (1000. + (((temperature.min(6500) - 2700) as f64) / 3800.) * 9000.) as u32
+}
+
+/// A queued set of per-device target states, applied concurrently by
+/// [`TuyaApi::apply_scene`] instead of one `await` at a time. The changes are
+/// not atomic: some devices can be updated while others fail.
+#[derive(Default)]
+pub struct Scene {
+    changes: Vec<(String, SceneChange)>,
+}
+
+enum SceneChange {
+    State(State),
+    Brightness(u8),
+    Color(HsbColor),
+    ColorTemperature(u32),
 }
 
+impl Scene {
+    pub fn new() -> Self {
+        Scene::default()
+    }
+
+    /// Queue turning any switchable device on or off.
+    pub fn set_state(&mut self, device: &impl Switchable, state: State) -> &mut Self {
+        self.changes
+            .push((device.id().to_owned(), SceneChange::State(state)));
+        self
+    }
+
+    pub fn set_brightness(&mut self, light: &Light, brightness: u8) -> &mut Self {
+        self.changes
+            .push((light.id().to_owned(), SceneChange::Brightness(brightness)));
+        self
+    }
+
+    pub fn set_color(&mut self, light: &Light, color: HsbColor) -> &mut Self {
+        self.changes
+            .push((light.id().to_owned(), SceneChange::Color(color)));
+        self
+    }
+
+    pub fn set_color_temperature(&mut self, light: &Light, temperature: u32) -> &mut Self {
+        self.changes.push((
+            light.id().to_owned(),
+            SceneChange::ColorTemperature(temperature),
+        ));
+        self
+    }
+}
+
+/// Per-device outcomes from [`TuyaApi::apply_scene`].
+#[derive(Debug)]
+pub struct SceneReport {
+    results: Vec<(String, Result<(), Error>)>,
+}
+
+impl SceneReport {
+    /// Whether every device in the scene was updated successfully.
+    pub fn is_success(&self) -> bool {
+        self.results.iter().all(|(_, result)| result.is_ok())
+    }
+
+    /// The device ids that failed to update, alongside their errors.
+    pub fn failures(&self) -> impl Iterator<Item = (&str, &Error)> {
+        self.results
+            .iter()
+            .filter_map(|(id, result)| result.as_ref().err().map(|error| (id.as_str(), error)))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum State {
     On,
     Off,
 }
 
+/// A color in the HSB/HSV space Tuya's `colorSet` command expects: `hue` in
+/// degrees (0-360), `saturation` as a fraction (0.0-1.0), and `brightness`
+/// on the device's own 0-1000 value range (matching the local-protocol `v`
+/// component used in [`crate::local`]).
+#[derive(Debug, Clone, Copy)]
 pub struct HsbColor {
-    pub brightness: u16,
-    pub saturation: u16,
     pub hue: u16,
+    pub saturation: f64,
+    pub brightness: u16,
+}
+
+impl HsbColor {
+    /// Convert from 24-bit RGB using the standard RGB→HSV formula.
+    pub fn from_rgb(r: u8, g: u8, b: u8) -> HsbColor {
+        let (r, g, b) = (r as f64 / 255., g as f64 / 255., b as f64 / 255.);
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+
+        let hue = if delta == 0. {
+            0.
+        } else if max == r {
+            60. * (((g - b) / delta).rem_euclid(6.))
+        } else if max == g {
+            60. * (((b - r) / delta) + 2.)
+        } else {
+            60. * (((r - g) / delta) + 4.)
+        };
+        let saturation = if max == 0. { 0. } else { delta / max };
+
+        HsbColor {
+            hue: hue.round() as u16,
+            saturation,
+            brightness: (max * 1000.).round() as u16,
+        }
+    }
+
+    /// Parse a `#rrggbb` or `rrggbb` hex color.
+    pub fn from_hex(hex: &str) -> Result<HsbColor, Error> {
+        let hex = hex.trim_start_matches('#');
+        if hex.len() != 6 {
+            return Err(Error::InvalidColor(format!(
+                "`{}` is not a 6-digit hex color",
+                hex
+            )));
+        }
+        let channel = |range: std::ops::Range<usize>| {
+            u8::from_str_radix(&hex[range], 16)
+                .map_err(|e| Error::InvalidColor(format!("`{}` is not valid hex: {}", hex, e)))
+        };
+        Ok(HsbColor::from_rgb(channel(0..2)?, channel(2..4)?, channel(4..6)?))
+    }
+
+    /// Convert back to 24-bit RGB, the inverse of [`HsbColor::from_rgb`].
+    pub fn to_rgb(&self) -> (u8, u8, u8) {
+        let s = self.saturation.clamp(0., 1.);
+        let v = self.brightness as f64 / 1000.;
+        let c = v * s;
+        let h = self.hue as f64 / 60.;
+        let x = c * (1. - (h % 2. - 1.).abs());
+        let m = v - c;
+        let (r, g, b) = match self.hue {
+            0..=59 => (c, x, 0.),
+            60..=119 => (x, c, 0.),
+            120..=179 => (0., c, x),
+            180..=239 => (0., x, c),
+            240..=299 => (x, 0., c),
+            _ => (c, 0., x),
+        };
+        (
+            ((r + m) * 255.).round() as u8,
+            ((g + m) * 255.).round() as u8,
+            ((b + m) * 255.).round() as u8,
+        )
+    }
+}
+
+#[cfg(test)]
+mod hsb_color_tests {
+    use super::HsbColor;
+
+    #[test]
+    fn black_has_no_brightness() {
+        let color = HsbColor::from_rgb(0, 0, 0);
+        assert_eq!(color.brightness, 0);
+        assert_eq!(color.saturation, 0.);
+        assert_eq!(color.to_rgb(), (0, 0, 0));
+    }
+
+    #[test]
+    fn white_has_no_saturation() {
+        let color = HsbColor::from_rgb(255, 255, 255);
+        assert_eq!(color.saturation, 0.);
+        assert_eq!(color.brightness, 1000);
+        assert_eq!(color.to_rgb(), (255, 255, 255));
+    }
+
+    #[test]
+    fn primary_hues_round_trip() {
+        for (r, g, b, hue) in [(255, 0, 0, 0), (0, 255, 0, 120), (0, 0, 255, 240)] {
+            let color = HsbColor::from_rgb(r, g, b);
+            assert_eq!(color.hue, hue);
+            assert_eq!(color.saturation, 1.);
+            assert_eq!(color.to_rgb(), (r, g, b));
+        }
+    }
+
+    #[test]
+    fn from_hex_accepts_leading_hash() {
+        assert_eq!(
+            HsbColor::from_hex("#ff0000").unwrap().to_rgb(),
+            HsbColor::from_hex("ff0000").unwrap().to_rgb(),
+        );
+    }
+
+    #[test]
+    fn from_hex_rejects_wrong_length() {
+        assert!(HsbColor::from_hex("#fff").is_err());
+    }
+
+    #[test]
+    fn from_hex_rejects_non_hex_digits() {
+        assert!(HsbColor::from_hex("zzzzzz").is_err());
+    }
 }
@@ -1,22 +1,22 @@
-use lights_tuya::{AccessToken, HsbColor, Light, TuyaApi};
+use lights_tuya::{Device, HsbColor, Session, TuyaApi};
 use serde::{Deserialize, Serialize};
 use smol::block_on;
 use std::io::{Read, Write};
 
 #[derive(Serialize, Deserialize)]
 struct DevicesFile {
-    devices: Vec<Light>,
+    devices: Vec<Device>,
 }
 
 fn main() {
     block_on(async move {
-        let key_path = std::path::Path::new("access_token");
-        let api = if key_path.exists() {
+        let session_path = std::path::Path::new("session.json");
+        let api = if session_path.exists() {
             let file = std::fs::OpenOptions::new()
                 .read(true)
-                .open(key_path)
+                .open(session_path)
                 .unwrap();
-            TuyaApi::from_token(AccessToken::read_from(file).unwrap())
+            TuyaApi::from_session(Session::read_from(file).unwrap())
         } else {
             let api = TuyaApi::new(
                 std::env::var("TUYA_USER").unwrap(),
@@ -27,19 +27,22 @@ fn main() {
             let file = std::fs::OpenOptions::new()
                 .create(true)
                 .write(true)
-                .open(key_path)
+                .open(session_path)
                 .unwrap();
-            api.dump_token().write_to(file).unwrap();
+            api.session().await.write_to(file).unwrap();
             api
         };
-        let devices_path = std::path::Path::new("devices.toml");
+        // Note: this is JSON, not TOML, because `Device`'s variants wrap a
+        // `#[serde(flatten)]`-ed `DeviceInfo` and the `toml` crate's
+        // serializer can't represent that shape.
+        let devices_path = std::path::Path::new("devices.json");
         let devices = if devices_path.exists() {
             let mut buf = String::new();
             std::fs::File::open(devices_path)
                 .unwrap()
                 .read_to_string(&mut buf)
                 .unwrap();
-            let DevicesFile { devices } = toml::from_str(&buf).unwrap();
+            let DevicesFile { devices } = serde_json::from_str(&buf).unwrap();
             devices
         } else {
             let devices = api.scan().await.unwrap();
@@ -49,7 +52,7 @@ fn main() {
                 .open(devices_path)
                 .unwrap()
                 .write_all(
-                    toml::to_string(&DevicesFile {
+                    serde_json::to_string(&DevicesFile {
                         devices: devices.clone(),
                     })
                     .unwrap()
@@ -59,19 +62,32 @@ fn main() {
             devices
         };
         let light_name = std::env::var("TUYA_LIGHT_NAME").unwrap();
-        for light in devices {
-            if light.name == light_name {
-                api.set_color(
-                    &light,
-                    HsbColor {
-                        hue: 0,
-                        saturation: 0.,
-                        brightness: 0,
-                    },
-                )
-                .await
-                .unwrap();
+        for device in devices {
+            if let Device::Light(light) = &device {
+                if light.name() == light_name {
+                    api.set_color(
+                        light,
+                        HsbColor {
+                            hue: 0,
+                            saturation: 0.,
+                            brightness: 0,
+                        },
+                    )
+                    .await
+                    .unwrap();
+                }
             }
         }
+        api.session()
+            .await
+            .write_to(
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .truncate(true)
+                    .open(session_path)
+                    .unwrap(),
+            )
+            .unwrap();
     });
 }